@@ -1,4 +1,5 @@
 use egg::{define_language, rewrite as rw, *};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::{self, Write};
 
@@ -122,6 +123,1364 @@ define_language! {
         // Sequence and NOP
         "seq" = Seq(Vec<Id>),
         "nop" = Nop,
+
+        // Marks where a label is defined, so branch/jump targets (which are just the
+        // label's `Var` symbol) survive rewriting and extraction.
+        "label" = LabelDef([Id; 1]),
+    }
+}
+
+/// Whether an instruction can be dropped once its result is unobserved, or whether it
+/// has an effect (store, branch, csr write, ...) that must always survive.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Effect {
+    Pure,
+    SideEffecting,
+}
+
+/// Def/use information for a single instruction, relative to its operand `Id`s in the
+/// enclosing `RecExpr`.
+struct InstrIo {
+    def: Option<Symbol>,
+    uses: Vec<Symbol>,
+    effect: Effect,
+    // Branches and jumps end a basic block; other side effects (stores, csrw) don't
+    // affect control flow and so don't need a block boundary of their own.
+    is_control_flow: bool,
+}
+
+/// Classifies `node`'s destination/source registers and effect, looking up operand
+/// `Id`s in `expr` to find the `Var` registers they resolve to. `x0` is reported like
+/// any other register; callers ignore it, since writes/reads to it are never meaningful.
+fn instr_io(node: &RiscvLang, expr: &RecExpr<RiscvLang>) -> InstrIo {
+    use RiscvLang::*;
+
+    let sym = |id: Id| match &expr[id] {
+        Var(s) => Some(*s),
+        _ => None,
+    };
+
+    let rtype = |d: Id, s1: Id, s2: Id| InstrIo {
+        def: sym(d),
+        uses: [s1, s2].into_iter().filter_map(sym).collect(),
+        effect: Effect::Pure,
+        is_control_flow: false,
+    };
+    let itype = |d: Id, s: Id, imm: Id| InstrIo {
+        def: sym(d),
+        uses: [s, imm].into_iter().filter_map(sym).collect(),
+        effect: Effect::Pure,
+        is_control_flow: false,
+    };
+    let branch = |s1: Id, s2: Id, label: Id| InstrIo {
+        def: None,
+        uses: [s1, s2, label].into_iter().filter_map(sym).collect(),
+        effect: Effect::SideEffecting,
+        is_control_flow: true,
+    };
+
+    match node {
+        Add([d, s1, s2]) | Sub([d, s1, s2]) | Mul([d, s1, s2]) | Div([d, s1, s2])
+        | Divu([d, s1, s2]) | Rem([d, s1, s2]) | Remu([d, s1, s2]) | Sll([d, s1, s2])
+        | Srl([d, s1, s2]) | Sra([d, s1, s2]) | And([d, s1, s2]) | Or([d, s1, s2])
+        | Xor([d, s1, s2]) | Slt([d, s1, s2]) | Sltu([d, s1, s2]) => rtype(*d, *s1, *s2),
+
+        Addi([d, s, i]) | Andi([d, s, i]) | Ori([d, s, i]) | Xori([d, s, i])
+        | Slti([d, s, i]) | Sltiu([d, s, i]) | Slli([d, s, i]) | Srli([d, s, i])
+        | Srai([d, s, i]) => itype(*d, *s, *i),
+
+        Lui([d, i]) | Auipc([d, i]) => InstrIo {
+            def: sym(*d),
+            uses: sym(*i).into_iter().collect(),
+            effect: Effect::Pure,
+            is_control_flow: false,
+        },
+
+        Lw([d, addr]) => InstrIo {
+            def: sym(*d),
+            uses: sym(*addr).into_iter().collect(),
+            effect: Effect::Pure,
+            is_control_flow: false,
+        },
+
+        Sw([src, addr]) => InstrIo {
+            def: None,
+            uses: [*src, *addr].into_iter().filter_map(sym).collect(),
+            effect: Effect::SideEffecting,
+            is_control_flow: false,
+        },
+
+        Beq([s1, s2, label]) | Bne([s1, s2, label]) | Blt([s1, s2, label])
+        | Bge([s1, s2, label]) | Bltu([s1, s2, label]) | Bgeu([s1, s2, label]) => {
+            branch(*s1, *s2, *label)
+        }
+
+        Jal([d, label]) => InstrIo {
+            def: sym(*d),
+            uses: sym(*label).into_iter().collect(),
+            effect: Effect::SideEffecting,
+            is_control_flow: true,
+        },
+        Jalr([d, target]) => InstrIo {
+            def: sym(*d),
+            uses: sym(*target).into_iter().collect(),
+            effect: Effect::SideEffecting,
+            is_control_flow: true,
+        },
+
+        Csrw([csr, src]) => InstrIo {
+            def: None,
+            uses: [*csr, *src].into_iter().filter_map(sym).collect(),
+            effect: Effect::SideEffecting,
+            is_control_flow: false,
+        },
+
+        Var(_) | Num(_) | Seq(_) | Nop | LabelDef(_) => InstrIo {
+            def: None,
+            uses: Vec::new(),
+            effect: Effect::Pure,
+            is_control_flow: false,
+        },
+    }
+}
+
+/// Splits a flat list of top-level `seq` children into basic blocks: a block ends
+/// right after a branch or jump, and also right before a label definition (a label
+/// is, by definition, something other code can jump to, so it's always a possible
+/// join point). The conservative all-live treatment at every block boundary keeps
+/// this sound even though we don't track which jumps actually target which labels.
+fn split_basic_blocks(instrs: &[Id], expr: &RecExpr<RiscvLang>) -> Vec<Vec<Id>> {
+    let mut blocks = Vec::new();
+    let mut current = Vec::new();
+
+    for &id in instrs {
+        if matches!(&expr[id], RiscvLang::LabelDef(_)) && !current.is_empty() {
+            blocks.push(std::mem::take(&mut current));
+        }
+        current.push(id);
+        if instr_io(&expr[id], expr).is_control_flow {
+            blocks.push(std::mem::take(&mut current));
+        }
+    }
+    if !current.is_empty() {
+        blocks.push(current);
+    }
+
+    blocks
+}
+
+/// Backward liveness over the top-level `seq`, turning any pure instruction whose
+/// destination is dead at that point into a `Nop`. `live_out` seeds the live set at
+/// the very end of the program (the caller-visible registers); every earlier block
+/// boundary is treated conservatively as all-registers-live, since this language
+/// doesn't yet track where control actually joins back up.
+fn liveness_dce(expr: &RecExpr<RiscvLang>, live_out: &[&str]) -> RecExpr<RiscvLang> {
+    let root = Id::from(expr.as_ref().len() - 1);
+    let mut nodes: Vec<RiscvLang> = expr.as_ref().to_vec();
+
+    let seq_children = match &nodes[usize::from(root)] {
+        RiscvLang::Seq(ids) => ids.clone(),
+        _ => return expr.clone(),
+    };
+
+    let blocks = split_basic_blocks(&seq_children, expr);
+    let x0 = Symbol::from("x0");
+    let live_out: HashSet<Symbol> = live_out.iter().map(|s| Symbol::from(*s)).collect();
+    let mut all_registers: HashSet<Symbol> = (0..32).map(|i| Symbol::from(format!("x{i}"))).collect();
+    // Virtual registers the allocator hasn't assigned yet are live across block
+    // boundaries too.
+    all_registers.extend(virtual_registers(expr));
+
+    for (block_idx, block) in blocks.iter().enumerate() {
+        let mut live = if block_idx + 1 == blocks.len() {
+            live_out.clone()
+        } else {
+            all_registers.clone()
+        };
+
+        for &id in block.iter().rev() {
+            let idx = usize::from(id);
+            let io = instr_io(&nodes[idx].clone(), expr);
+
+            let dead = io.effect == Effect::Pure
+                && matches!(io.def, Some(d) if d != x0 && !live.contains(&d));
+            if dead {
+                nodes[idx] = RiscvLang::Nop;
+                continue;
+            }
+
+            if let Some(d) = io.def {
+                live.remove(&d);
+            }
+            for u in io.uses {
+                if u != x0 {
+                    live.insert(u);
+                }
+            }
+        }
+    }
+
+    RecExpr::from(nodes)
+}
+
+/// The physical registers the Chaitin-Briggs allocator is allowed to hand out:
+/// the caller-saved temporaries and the callee-saved registers, excluding `x0`
+/// (hardwired zero), `sp`/`gp`/`tp` (x2-x4) and `ra` (x1). `x30` and `x31` are
+/// deliberately held back as scratch space for spill reload/store sequences (see
+/// `SPILL_SCRATCH_REGS`) so that code materializing a spilled value never itself
+/// needs a register under allocation.
+const ALLOCATABLE_REGS: &[&str] = &[
+    "x5", "x6", "x7", "x28", "x29", // caller-saved temporaries
+    "x9", "x18", "x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26", "x27", // callee-saved
+];
+/// Scratch registers `spill_to_stack` reloads spilled operands into. An instruction
+/// can read two *different* spilled virtuals at once (e.g. `add d, v0, v1`), so one
+/// scratch register isn't enough: reloading both into the same register would have
+/// the second overwrite the first before the instruction executes. Two is enough
+/// since no instruction in this language has more than two register sources.
+const SPILL_SCRATCH_REGS: [&str; 2] = ["x30", "x31"];
+
+/// Whether `sym` already names a physical register (`x0`..`x31`), as opposed to a
+/// virtual register the allocator still needs to assign.
+fn is_physical_reg(sym: Symbol) -> bool {
+    let s = sym.as_str();
+    s.len() >= 2
+        && s.starts_with('x')
+        && s[1..].chars().all(|c| c.is_ascii_digit())
+        && s[1..].parse::<u32>().map_or(false, |n| n < 32)
+}
+
+/// Whether `sym` is one of `spill_to_stack`'s synthetic stack-address operands
+/// (`"<offset>(x2)"`), as opposed to a register name. These are `Var`s too (so
+/// `lw`/`sw` can hold them in an operand position), but they must never re-enter
+/// allocation as a virtual register or a later spill round could recolor the
+/// address itself to some unrelated physical register.
+fn is_spill_address(sym: Symbol) -> bool {
+    sym.as_str().contains('(')
+}
+
+/// Every symbol that names a label rather than a register: every `LabelDef` child,
+/// plus every branch/jump instruction's label operand. Labels are `Var`s too (the
+/// language has no separate symbol sort for them), so without this exclusion the
+/// allocator can't tell a branch target from a virtual register and will happily
+/// recolor it, destroying the control flow it's part of.
+fn label_symbols(expr: &RecExpr<RiscvLang>) -> HashSet<Symbol> {
+    use RiscvLang::*;
+    let sym = |id: Id| match &expr[id] {
+        Var(s) => Some(*s),
+        _ => None,
+    };
+    expr.as_ref()
+        .iter()
+        .filter_map(|n| match n {
+            LabelDef([l]) => sym(*l),
+            Beq([_, _, l]) | Bne([_, _, l]) | Blt([_, _, l]) | Bge([_, _, l])
+            | Bltu([_, _, l]) | Bgeu([_, _, l]) | Jal([_, l]) => sym(*l),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Every distinct virtual register (a `Var` that isn't a physical register name, a
+/// synthetic spill-slot address, or a label) referenced anywhere in `expr`.
+fn virtual_registers(expr: &RecExpr<RiscvLang>) -> HashSet<Symbol> {
+    let labels = label_symbols(expr);
+    expr.as_ref()
+        .iter()
+        .filter_map(|n| match n {
+            RiscvLang::Var(s)
+                if !is_physical_reg(*s) && !is_spill_address(*s) && !labels.contains(s) =>
+            {
+                Some(*s)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Builds the interference graph over `virtuals`: two virtual registers interfere if
+/// one is defined while the other is live. Computes real liveness within each block
+/// by walking it backward from its live-out set (empty at the end of the program,
+/// all-virtuals-live at every earlier block boundary, the same conservative
+/// fallback `liveness_dce` uses for unknown successors) rather than assuming every
+/// virtual is live throughout. Also returns a per-register use count, used as the
+/// spill-cost heuristic.
+fn build_interference_graph(
+    expr: &RecExpr<RiscvLang>,
+    virtuals: &HashSet<Symbol>,
+) -> (HashMap<Symbol, HashSet<Symbol>>, HashMap<Symbol, u32>) {
+    let root = Id::from(expr.as_ref().len() - 1);
+    let seq_children = match &expr[root] {
+        RiscvLang::Seq(ids) => ids.clone(),
+        _ => Vec::new(),
+    };
+    let blocks = split_basic_blocks(&seq_children, expr);
+
+    let mut graph: HashMap<Symbol, HashSet<Symbol>> =
+        virtuals.iter().map(|v| (*v, HashSet::new())).collect();
+    let mut use_counts: HashMap<Symbol, u32> = HashMap::new();
+
+    for (block_idx, block) in blocks.iter().enumerate() {
+        // No virtual register is ever observed past the end of the program (unlike
+        // the physical ABI registers `liveness_dce`'s `live_out` tracks), so the
+        // true live-out set there is empty; every earlier block boundary still
+        // falls back to all-virtuals-live, the same conservative choice
+        // `liveness_dce` makes for the registers it tracks.
+        let mut live: HashSet<Symbol> = if block_idx + 1 == blocks.len() {
+            HashSet::new()
+        } else {
+            virtuals.clone()
+        };
+
+        for &id in block.iter().rev() {
+            let io = instr_io(&expr[id], expr);
+
+            if let Some(d) = io.def {
+                if virtuals.contains(&d) {
+                    for v in live.iter().filter(|v| **v != d) {
+                        graph.get_mut(&d).unwrap().insert(*v);
+                        graph.get_mut(v).unwrap().insert(d);
+                    }
+                    live.remove(&d);
+                }
+            }
+            for u in io.uses {
+                if virtuals.contains(&u) {
+                    *use_counts.entry(u).or_insert(0) += 1;
+                    live.insert(u);
+                }
+            }
+        }
+    }
+
+    (graph, use_counts)
+}
+
+/// Chaitin-Briggs simplify/select: repeatedly push any node whose remaining degree is
+/// below `ALLOCATABLE_REGS.len()` onto the stack; if none qualifies, optimistically
+/// push the node with the worst degree-to-use-count ratio as a potential spill. Then
+/// pop the stack, giving each node the first register in `ALLOCATABLE_REGS` order not
+/// already taken by a colored neighbor. A node that runs out of registers at pop time
+/// becomes an actual spill.
+fn color_graph(
+    graph: HashMap<Symbol, HashSet<Symbol>>,
+    use_counts: &HashMap<Symbol, u32>,
+) -> (HashMap<Symbol, Symbol>, Vec<Symbol>) {
+    let k = ALLOCATABLE_REGS.len();
+    let mut remaining: HashSet<Symbol> = graph.keys().copied().collect();
+    let mut stack: Vec<(Symbol, bool)> = Vec::new(); // (register, is_potential_spill)
+
+    while let Some(&victim) = remaining.iter().find(|n| {
+        graph[*n].iter().filter(|m| remaining.contains(*m)).count() < k
+    }) {
+        remaining.remove(&victim);
+        stack.push((victim, false));
+    }
+    while !remaining.is_empty() {
+        let victim = *remaining
+            .iter()
+            .max_by(|a, b| {
+                let cost = |n: &Symbol| {
+                    let degree = graph[n].iter().filter(|m| remaining.contains(*m)).count() as f64;
+                    degree / *use_counts.get(n).unwrap_or(&1) as f64
+                };
+                cost(a).partial_cmp(&cost(b)).unwrap()
+            })
+            .unwrap();
+        remaining.remove(&victim);
+        stack.push((victim, true));
+
+        while let Some(&next) = remaining.iter().find(|n| {
+            graph[*n].iter().filter(|m| remaining.contains(*m)).count() < k
+        }) {
+            remaining.remove(&next);
+            stack.push((next, false));
+        }
+    }
+
+    let mut colors: HashMap<Symbol, Symbol> = HashMap::new();
+    let mut spills = Vec::new();
+
+    while let Some((node, _)) = stack.pop() {
+        let used: HashSet<&str> = graph[&node]
+            .iter()
+            .filter_map(|n| colors.get(n))
+            .map(Symbol::as_str)
+            .collect();
+        match ALLOCATABLE_REGS.iter().find(|r| !used.contains(*r)) {
+            Some(reg) => {
+                colors.insert(node, Symbol::from(*reg));
+            }
+            None => spills.push(node),
+        }
+    }
+
+    (colors, spills)
+}
+
+/// Reconstructs `node` with the same variant but `new_children` in place of its
+/// original operands.
+fn rebuild_with_children(node: &RiscvLang, new_children: &[Id]) -> RiscvLang {
+    let mut n = node.clone();
+    for (slot, id) in n.children_mut().iter_mut().zip(new_children) {
+        *slot = *id;
+    }
+    n
+}
+
+/// Rewrites every def/use of a spilled virtual register to go through a stack slot:
+/// loads reload it into a scratch register (one of `SPILL_SCRATCH_REGS`, distinct per
+/// distinct spilled source within the instruction) right before an instruction that
+/// reads it, and a store writes the scratch register back out right after an
+/// instruction that defines it.
+fn spill_to_stack(expr: &RecExpr<RiscvLang>, spills: &[Symbol]) -> RecExpr<RiscvLang> {
+    let root = Id::from(expr.as_ref().len() - 1);
+    let seq_children = match &expr[root] {
+        RiscvLang::Seq(ids) => ids.clone(),
+        _ => return expr.clone(),
+    };
+
+    let slots: HashMap<Symbol, i32> = spills
+        .iter()
+        .enumerate()
+        .map(|(i, s)| (*s, (i as i32) * 4))
+        .collect();
+
+    let mut nodes: Vec<RiscvLang> = Vec::new();
+    let push = |nodes: &mut Vec<RiscvLang>, node: RiscvLang| -> Id {
+        nodes.push(node);
+        Id::from(nodes.len() - 1)
+    };
+    let mut new_children = Vec::new();
+
+    for &id in &seq_children {
+        let node = expr[id].clone();
+        let io = instr_io(&node, expr);
+
+        // Assign each distinct spilled source register read by this instruction its
+        // own scratch register, in first-occurrence order, so an instruction reading
+        // two different spilled virtuals (e.g. `add d, v0, v1`) doesn't have the
+        // second reload clobber the first before use.
+        // Every instruction with a destination has it as its first operand.
+        let has_def = io.def.is_some();
+        let mut scratch_for: HashMap<Symbol, Symbol> = HashMap::new();
+        let mut spilled_sources: Vec<Symbol> = Vec::new();
+        for (pos, c) in node.children().iter().enumerate() {
+            if has_def && pos == 0 {
+                continue;
+            }
+            if let RiscvLang::Var(s) = &expr[*c] {
+                if slots.contains_key(s) && !scratch_for.contains_key(s) {
+                    let reg = SPILL_SCRATCH_REGS[scratch_for.len()];
+                    scratch_for.insert(*s, Symbol::from(reg));
+                    spilled_sources.push(*s);
+                }
+            }
+        }
+
+        for s in &spilled_sources {
+            let reg = scratch_for[s];
+            let addr = push(&mut nodes, RiscvLang::Var(Symbol::from(format!("{}(x2)", slots[s]))));
+            let dest = push(&mut nodes, RiscvLang::Var(reg));
+            new_children.push(push(&mut nodes, RiscvLang::Lw([dest, addr])));
+        }
+
+        let mut children = Vec::new();
+        for (pos, c) in node.children().iter().enumerate() {
+            let copied = match &expr[*c] {
+                // A spilled destination is written to the scratch register the store
+                // below copies out to its stack slot.
+                RiscvLang::Var(s) if has_def && pos == 0 && slots.contains_key(s) => {
+                    push(&mut nodes, RiscvLang::Var(Symbol::from(SPILL_SCRATCH_REGS[0])))
+                }
+                RiscvLang::Var(s) if !(has_def && pos == 0) && scratch_for.contains_key(s) => {
+                    push(&mut nodes, RiscvLang::Var(scratch_for[s]))
+                }
+                other => push(&mut nodes, other.clone()),
+            };
+            children.push(copied);
+        }
+        new_children.push(push(&mut nodes, rebuild_with_children(&node, &children)));
+
+        if let Some(d) = io.def {
+            if let Some(offset) = slots.get(&d) {
+                let addr = push(&mut nodes, RiscvLang::Var(Symbol::from(format!("{offset}(x2)"))));
+                let src = push(&mut nodes, RiscvLang::Var(Symbol::from(SPILL_SCRATCH_REGS[0])));
+                new_children.push(push(&mut nodes, RiscvLang::Sw([src, addr])));
+            }
+        }
+    }
+
+    push(&mut nodes, RiscvLang::Seq(new_children));
+    RecExpr::from(nodes)
+}
+
+/// Replaces every virtual register `Var` with the physical register it was colored to.
+fn rename_registers(expr: &RecExpr<RiscvLang>, colors: &HashMap<Symbol, Symbol>) -> RecExpr<RiscvLang> {
+    let nodes: Vec<RiscvLang> = expr
+        .as_ref()
+        .iter()
+        .map(|n| match n {
+            RiscvLang::Var(s) => RiscvLang::Var(*colors.get(s).unwrap_or(s)),
+            other => other.clone(),
+        })
+        .collect();
+    RecExpr::from(nodes)
+}
+
+/// Allocates physical registers for every virtual `Var` in `expr`, spilling to the
+/// stack and re-coloring as needed until every virtual register either gets a
+/// physical register or a stack slot.
+fn allocate_registers(expr: &RecExpr<RiscvLang>) -> RecExpr<RiscvLang> {
+    let mut current = expr.clone();
+
+    loop {
+        let virtuals = virtual_registers(&current);
+        if virtuals.is_empty() {
+            return current;
+        }
+
+        let (graph, use_counts) = build_interference_graph(&current, &virtuals);
+        let (colors, spills) = color_graph(graph, &use_counts);
+
+        if spills.is_empty() {
+            return rename_registers(&current, &colors);
+        }
+        current = spill_to_stack(&current, &spills);
+    }
+}
+
+/// ABI register names in their conventional order, paired with the `x`-numbered
+/// symbol the rest of this crate works in. `s0` and `fp` are both aliases for `x8`;
+/// `s0` is listed first so it's the name `x_to_abi` picks when emitting.
+const ABI_TO_X: &[(&str, &str)] = &[
+    ("zero", "x0"),
+    ("ra", "x1"),
+    ("sp", "x2"),
+    ("gp", "x3"),
+    ("tp", "x4"),
+    ("t0", "x5"),
+    ("t1", "x6"),
+    ("t2", "x7"),
+    ("s0", "x8"),
+    ("fp", "x8"),
+    ("s1", "x9"),
+    ("a0", "x10"),
+    ("a1", "x11"),
+    ("a2", "x12"),
+    ("a3", "x13"),
+    ("a4", "x14"),
+    ("a5", "x15"),
+    ("a6", "x16"),
+    ("a7", "x17"),
+    ("s2", "x18"),
+    ("s3", "x19"),
+    ("s4", "x20"),
+    ("s5", "x21"),
+    ("s6", "x22"),
+    ("s7", "x23"),
+    ("s8", "x24"),
+    ("s9", "x25"),
+    ("s10", "x26"),
+    ("s11", "x27"),
+    ("t3", "x28"),
+    ("t4", "x29"),
+    ("t5", "x30"),
+    ("t6", "x31"),
+];
+
+/// Normalizes an ABI register name (`a0`, `sp`, `fp`, ...) to its `x`-numbered form;
+/// anything that isn't an ABI name (an immediate, a label) passes through unchanged.
+fn abi_to_x(name: &str) -> String {
+    ABI_TO_X
+        .iter()
+        .find(|(abi, _)| *abi == name)
+        .map(|(_, x)| x.to_string())
+        .unwrap_or_else(|| name.to_string())
+}
+
+/// The inverse of [`abi_to_x`]: prints an `x`-numbered register as its ABI name.
+/// Leaves anything that isn't of the form `x<digits>` (a label, an immediate)
+/// unchanged.
+fn x_to_abi(name: &str) -> String {
+    if !is_physical_reg(Symbol::from(name)) {
+        return name.to_string();
+    }
+    ABI_TO_X
+        .iter()
+        .find(|(_, x)| *x == name)
+        .map(|(abi, _)| abi.to_string())
+        .unwrap_or_else(|| name.to_string())
+}
+
+/// Normalizes a single register-shaped token. Leaves non-register text (immediates,
+/// labels) untouched.
+fn normalize_register_token(tok: &str) -> String {
+    if ABI_TO_X.iter().any(|(abi, _)| *abi == tok) {
+        abi_to_x(tok)
+    } else {
+        tok.to_string()
+    }
+}
+
+/// Normalizes an assembly operand: a plain register name, or an offset-addressing
+/// form like `4(sp)`/`-8(a0)`, whose register part also gets normalized.
+fn normalize_operand(tok: &str) -> String {
+    if let Some(open) = tok.find('(') {
+        if tok.ends_with(')') {
+            let offset = &tok[..open];
+            let reg = &tok[open + 1..tok.len() - 1];
+            return format!("{offset}({})", normalize_register_token(reg));
+        }
+    }
+    normalize_register_token(tok)
+}
+
+/// The inverse of [`normalize_operand`] for emission: converts the register part of
+/// a plain register or `offset(reg)` operand back to its ABI name.
+fn format_operand(sym: Symbol) -> String {
+    let s = sym.as_str();
+    if let Some(open) = s.find('(') {
+        if s.ends_with(')') {
+            let offset = &s[..open];
+            let reg = &s[open + 1..s.len() - 1];
+            return format!("{offset}({})", x_to_abi(reg));
+        }
+    }
+    x_to_abi(s)
+}
+
+/// Parses a decimal or `0x`/`0X`-prefixed hex immediate, with an optional leading `-`.
+fn parse_immediate(text: &str) -> Result<i32, std::num::ParseIntError> {
+    let (neg, text) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let value = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => i32::from_str_radix(hex, 16)?,
+        None => text.parse::<i32>()?,
+    };
+    Ok(if neg { -value } else { value })
+}
+
+/// Strips a trailing `#` or `;` line comment, GNU assembler style.
+fn strip_comment(line: &str) -> &str {
+    let cut = line.find(|c: char| c == '#' || c == ';').unwrap_or(line.len());
+    &line[..cut]
+}
+
+fn push_node(nodes: &mut Vec<RiscvLang>, node: RiscvLang) -> Id {
+    nodes.push(node);
+    Id::from(nodes.len() - 1)
+}
+
+/// Parses one operand as an immediate if it looks like one, otherwise as a
+/// (possibly offset-addressed) register or label.
+fn push_operand(nodes: &mut Vec<RiscvLang>, text: &str) -> Id {
+    match parse_immediate(text) {
+        Ok(n) => push_node(nodes, RiscvLang::Num(n)),
+        Err(_) => push_node(nodes, RiscvLang::Var(Symbol::from(normalize_operand(text)))),
+    }
+}
+
+/// Builds the `RiscvLang` node for one assembly instruction, expanding the GNU
+/// pseudo-instructions (`mv`, `li`, `j`, `ret`, `not`, `neg`, `seqz`, ...) into the
+/// canonical forms the rewrite rules are written against.
+fn build_instr(mnemonic: &str, operands: &[&str], nodes: &mut Vec<RiscvLang>) -> Id {
+    use RiscvLang::*;
+
+    macro_rules! op {
+        ($i:expr) => {
+            push_operand(nodes, operands[$i])
+        };
+    }
+
+    match mnemonic {
+        "add" => { let n = Add([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "sub" => { let n = Sub([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "mul" => { let n = Mul([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "div" => { let n = Div([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "divu" => { let n = Divu([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "rem" => { let n = Rem([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "remu" => { let n = Remu([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "sll" => { let n = Sll([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "srl" => { let n = Srl([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "sra" => { let n = Sra([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "and" => { let n = And([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "or" => { let n = Or([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "xor" => { let n = Xor([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "slt" => { let n = Slt([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "sltu" => { let n = Sltu([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+
+        "addi" => { let n = Addi([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "andi" => { let n = Andi([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "ori" => { let n = Ori([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "xori" => { let n = Xori([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "slti" => { let n = Slti([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "sltiu" => { let n = Sltiu([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "slli" => { let n = Slli([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "srli" => { let n = Srli([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "srai" => { let n = Srai([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "lui" => { let n = Lui([op!(0), op!(1)]); push_node(nodes, n) }
+        "auipc" => { let n = Auipc([op!(0), op!(1)]); push_node(nodes, n) }
+
+        "beq" => { let n = Beq([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "bne" => { let n = Bne([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "blt" => { let n = Blt([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "bge" => { let n = Bge([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "bltu" => { let n = Bltu([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+        "bgeu" => { let n = Bgeu([op!(0), op!(1), op!(2)]); push_node(nodes, n) }
+
+        // `jal label` implies the default link register, ra (x1).
+        "jal" if operands.len() == 1 => {
+            let dest = push_node(nodes, Var(Symbol::from("x1")));
+            let label = op!(0);
+            push_node(nodes, Jal([dest, label]))
+        }
+        "jal" => { let n = Jal([op!(0), op!(1)]); push_node(nodes, n) }
+
+        "jalr" if operands.len() == 1 => {
+            let dest = push_node(nodes, Var(Symbol::from("x1")));
+            let target = op!(0);
+            push_node(nodes, Jalr([dest, target]))
+        }
+        "jalr" => { let n = Jalr([op!(0), op!(1)]); push_node(nodes, n) }
+
+        "lw" => { let n = Lw([op!(0), op!(1)]); push_node(nodes, n) }
+        "sw" => { let n = Sw([op!(0), op!(1)]); push_node(nodes, n) }
+        "csrw" => { let n = Csrw([op!(0), op!(1)]); push_node(nodes, n) }
+
+        "mv" => {
+            let zero = push_node(nodes, Num(0));
+            let n = Addi([op!(0), op!(1), zero]);
+            push_node(nodes, n)
+        }
+        "li" => {
+            let x0 = push_node(nodes, Var(Symbol::from("x0")));
+            let n = Addi([op!(0), x0, op!(1)]);
+            push_node(nodes, n)
+        }
+        "not" => {
+            let neg_one = push_node(nodes, Num(-1));
+            let n = Xori([op!(0), op!(1), neg_one]);
+            push_node(nodes, n)
+        }
+        "neg" => {
+            let x0 = push_node(nodes, Var(Symbol::from("x0")));
+            let n = Sub([op!(0), x0, op!(1)]);
+            push_node(nodes, n)
+        }
+        "seqz" => {
+            let one = push_node(nodes, Num(1));
+            let n = Sltiu([op!(0), op!(1), one]);
+            push_node(nodes, n)
+        }
+        "j" => {
+            let x0 = push_node(nodes, Var(Symbol::from("x0")));
+            let n = Jal([x0, op!(0)]);
+            push_node(nodes, n)
+        }
+        "ret" => {
+            let x0 = push_node(nodes, Var(Symbol::from("x0")));
+            let ra = push_node(nodes, Var(Symbol::from("x1")));
+            push_node(nodes, Jalr([x0, ra]))
+        }
+        "nop" => push_node(nodes, Nop),
+
+        other => panic!("parse_asm: unsupported mnemonic `{other}`"),
+    }
+}
+
+/// Parses conventional RISC-V assembly (`label:` definitions, comma-separated
+/// operands, ABI register names, decimal/hex immediates, `offset(reg)` addressing)
+/// into a single top-level `Seq`, so the optimizer can run directly on `gcc -S`
+/// output.
+fn parse_asm(input: &str) -> RecExpr<RiscvLang> {
+    let mut nodes: Vec<RiscvLang> = Vec::new();
+    let mut seq_children = Vec::new();
+
+    for raw_line in input.lines() {
+        let line = strip_comment(raw_line).trim();
+        // Directives start with `.`; so do local labels like `.L2:`, which are kept.
+        if line.is_empty() || (line.starts_with('.') && !line.ends_with(':')) {
+            continue;
+        }
+
+        if let Some(label) = line.strip_suffix(':') {
+            let label_id = push_node(&mut nodes, RiscvLang::Var(Symbol::from(label.trim())));
+            seq_children.push(push_node(&mut nodes, RiscvLang::LabelDef([label_id])));
+            continue;
+        }
+
+        let (mnemonic, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let operands: Vec<&str> = rest.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+        seq_children.push(build_instr(mnemonic, &operands, &mut nodes));
+    }
+
+    push_node(&mut nodes, RiscvLang::Seq(seq_children));
+    RecExpr::from(nodes)
+}
+
+/// Prints one instruction node as a line of conventional RISC-V assembly, recognizing
+/// canonical encodings that have a standard pseudo-instruction (`mv`, `li`, `j`,
+/// `ret`, `not`, `neg`, `seqz`) and printing that instead of the verbose form the
+/// rewrite rules actually produce.
+fn emit_instr(node: &RiscvLang, expr: &RecExpr<RiscvLang>) -> String {
+    use RiscvLang::*;
+
+    let operand = |id: Id| match &expr[id] {
+        Var(s) => format_operand(*s),
+        Num(n) => n.to_string(),
+        _ => unreachable!("operand position must be a leaf"),
+    };
+
+    let is_zero_reg = |id: Id| matches!(&expr[id], Var(s) if s.as_str() == "x0");
+    let is_num = |id: Id, n: i32| matches!(&expr[id], Num(v) if *v == n);
+
+    match node {
+        Add([d, s1, s2]) => format!("add {}, {}, {}", operand(*d), operand(*s1), operand(*s2)),
+        // sub d, x0, s => neg d, s
+        Sub([d, s1, s2]) if is_zero_reg(*s1) => format!("neg {}, {}", operand(*d), operand(*s2)),
+        Sub([d, s1, s2]) => format!("sub {}, {}, {}", operand(*d), operand(*s1), operand(*s2)),
+        Mul([d, s1, s2]) => format!("mul {}, {}, {}", operand(*d), operand(*s1), operand(*s2)),
+        Div([d, s1, s2]) => format!("div {}, {}, {}", operand(*d), operand(*s1), operand(*s2)),
+        Divu([d, s1, s2]) => format!("divu {}, {}, {}", operand(*d), operand(*s1), operand(*s2)),
+        Rem([d, s1, s2]) => format!("rem {}, {}, {}", operand(*d), operand(*s1), operand(*s2)),
+        Remu([d, s1, s2]) => format!("remu {}, {}, {}", operand(*d), operand(*s1), operand(*s2)),
+        Sll([d, s1, s2]) => format!("sll {}, {}, {}", operand(*d), operand(*s1), operand(*s2)),
+        Srl([d, s1, s2]) => format!("srl {}, {}, {}", operand(*d), operand(*s1), operand(*s2)),
+        Sra([d, s1, s2]) => format!("sra {}, {}, {}", operand(*d), operand(*s1), operand(*s2)),
+        And([d, s1, s2]) => format!("and {}, {}, {}", operand(*d), operand(*s1), operand(*s2)),
+        Or([d, s1, s2]) => format!("or {}, {}, {}", operand(*d), operand(*s1), operand(*s2)),
+        Xor([d, s1, s2]) => format!("xor {}, {}, {}", operand(*d), operand(*s1), operand(*s2)),
+        Slt([d, s1, s2]) => format!("slt {}, {}, {}", operand(*d), operand(*s1), operand(*s2)),
+        Sltu([d, s1, s2]) => format!("sltu {}, {}, {}", operand(*d), operand(*s1), operand(*s2)),
+
+        // addi d, x0, imm => li d, imm (covers the addi d, x0, 0 => li d, 0 case too)
+        Addi([d, s, i]) if is_zero_reg(*s) => format!("li {}, {}", operand(*d), operand(*i)),
+        // addi d, s, 0 => mv d, s
+        Addi([d, s, i]) if is_num(*i, 0) => format!("mv {}, {}", operand(*d), operand(*s)),
+        Addi([d, s, i]) => format!("addi {}, {}, {}", operand(*d), operand(*s), operand(*i)),
+        Andi([d, s, i]) => format!("andi {}, {}, {}", operand(*d), operand(*s), operand(*i)),
+        Ori([d, s, i]) => format!("ori {}, {}, {}", operand(*d), operand(*s), operand(*i)),
+        // xori d, s, -1 => not d, s
+        Xori([d, s, i]) if is_num(*i, -1) => format!("not {}, {}", operand(*d), operand(*s)),
+        Xori([d, s, i]) => format!("xori {}, {}, {}", operand(*d), operand(*s), operand(*i)),
+        Slti([d, s, i]) => format!("slti {}, {}, {}", operand(*d), operand(*s), operand(*i)),
+        // sltiu d, s, 1 => seqz d, s
+        Sltiu([d, s, i]) if is_num(*i, 1) => format!("seqz {}, {}", operand(*d), operand(*s)),
+        Sltiu([d, s, i]) => format!("sltiu {}, {}, {}", operand(*d), operand(*s), operand(*i)),
+        Slli([d, s, i]) => format!("slli {}, {}, {}", operand(*d), operand(*s), operand(*i)),
+        Srli([d, s, i]) => format!("srli {}, {}, {}", operand(*d), operand(*s), operand(*i)),
+        Srai([d, s, i]) => format!("srai {}, {}, {}", operand(*d), operand(*s), operand(*i)),
+        Lui([d, i]) => format!("lui {}, {}", operand(*d), operand(*i)),
+        Auipc([d, i]) => format!("auipc {}, {}", operand(*d), operand(*i)),
+
+        Beq([s1, s2, l]) => format!("beq {}, {}, {}", operand(*s1), operand(*s2), operand(*l)),
+        Bne([s1, s2, l]) => format!("bne {}, {}, {}", operand(*s1), operand(*s2), operand(*l)),
+        Blt([s1, s2, l]) => format!("blt {}, {}, {}", operand(*s1), operand(*s2), operand(*l)),
+        Bge([s1, s2, l]) => format!("bge {}, {}, {}", operand(*s1), operand(*s2), operand(*l)),
+        Bltu([s1, s2, l]) => format!("bltu {}, {}, {}", operand(*s1), operand(*s2), operand(*l)),
+        Bgeu([s1, s2, l]) => format!("bgeu {}, {}, {}", operand(*s1), operand(*s2), operand(*l)),
+
+        // jal x0, label => j label
+        Jal([d, l]) if is_zero_reg(*d) => format!("j {}", operand(*l)),
+        Jal([d, l]) => format!("jal {}, {}", operand(*d), operand(*l)),
+        // jalr x0, ra => ret
+        Jalr([d, t]) if is_zero_reg(*d) && matches!(&expr[*t], Var(s) if s.as_str() == "x1") => {
+            "ret".to_string()
+        }
+        Jalr([d, t]) => format!("jalr {}, {}", operand(*d), operand(*t)),
+
+        Lw([d, addr]) => format!("lw {}, {}", operand(*d), operand(*addr)),
+        Sw([s, addr]) => format!("sw {}, {}", operand(*s), operand(*addr)),
+        Csrw([c, s]) => format!("csrw {}, {}", operand(*c), operand(*s)),
+
+        LabelDef([name]) => format!("{}:", operand(*name)),
+
+        Nop => "nop".to_string(),
+
+        Var(_) | Num(_) | Seq(_) => unreachable!("not a standalone instruction"),
+    }
+}
+
+/// Prints the top-level `Seq` as conventional RISC-V assembly, skipping any
+/// instruction that's been reduced to a `Nop` (there's nothing left to round-trip
+/// through an address for, since labels are tracked symbolically, not positionally).
+fn emit_asm(expr: &RecExpr<RiscvLang>) -> String {
+    let root = Id::from(expr.as_ref().len() - 1);
+    let seq_children = match &expr[root] {
+        RiscvLang::Seq(ids) => ids.clone(),
+        _ => return String::new(),
+    };
+
+    seq_children
+        .iter()
+        .map(|&id| &expr[id])
+        .filter(|node| !matches!(node, RiscvLang::Nop))
+        .map(|node| emit_instr(node, expr))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// A `CostFunction` that models per-opcode cycle latency instead of treating every
+/// instruction as equally expensive, so extraction can prefer a cheaper instruction
+/// mix (e.g. a shift over a `mul`/`div` by a power of two) instead of just the
+/// smallest AST. Summed over children the same way `AstSize` sums node counts.
+struct Latency;
+
+impl CostFunction<RiscvLang> for Latency {
+    type Cost = usize;
+
+    fn cost<C>(&mut self, enode: &RiscvLang, mut costs: C) -> Self::Cost
+    where
+        C: FnMut(Id) -> Self::Cost,
+    {
+        use RiscvLang::*;
+        let self_cost = match enode {
+            Mul(_) => 3,
+            Div(_) | Divu(_) | Rem(_) | Remu(_) => 20,
+            Lw(_) | Sw(_) => 4,
+            Beq(_) | Bne(_) | Blt(_) | Bge(_) | Bltu(_) | Bgeu(_) | Jal(_) | Jalr(_) => 2,
+            _ => 1,
+        };
+        enode.fold(self_cost, |sum, id| sum + costs(id))
+    }
+}
+
+/// Which `CostFunction`/extractor to run, selected with `--cost astsize|latency`
+/// (defaults to `astsize` to match the crate's historical behavior).
+///
+/// There's no `Lp` variant: egg's ILP-based `LpExtractor` needs a `LpCostFunction`
+/// impl (a distinct trait from the `CostFunction` `Latency` implements) and lives
+/// behind egg's non-default `lp` cargo feature, which pulls in a system CBC solver
+/// library (`coin_cbc`). Neither is wired up in this crate yet, so `--cost lp`
+/// falls back to `astsize` like any other unrecognized value until both are added.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ExtractKind {
+    AstSize,
+    Latency,
+}
+
+fn extract_kind_from_args() -> ExtractKind {
+    let args: Vec<String> = std::env::args().collect();
+    for pair in args.windows(2) {
+        if pair[0] == "--cost" {
+            return match pair[1].as_str() {
+                "latency" => ExtractKind::Latency,
+                _ => ExtractKind::AstSize,
+            };
+        }
+    }
+    ExtractKind::AstSize
+}
+
+/// Tracks, for every `Num` eclass, the constant it holds, so rewrites can condition
+/// on "both operands are known constants" instead of relying on patterns matching
+/// arithmetic directly (egg patterns can't do that).
+#[derive(Default)]
+struct ConstantFold;
+
+impl Analysis<RiscvLang> for ConstantFold {
+    type Data = Option<i32>;
+
+    fn make(_egraph: &EGraph<RiscvLang, Self>, enode: &RiscvLang) -> Self::Data {
+        match enode {
+            RiscvLang::Num(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn merge(&mut self, a: &mut Self::Data, b: Self::Data) -> DidMerge {
+        match (&*a, b) {
+            (Some(x), Some(y)) => {
+                debug_assert_eq!(*x, y, "a Num eclass merged with two different constants");
+                DidMerge(false, false)
+            }
+            (Some(_), None) => DidMerge(false, false),
+            (None, Some(y)) => {
+                *a = Some(y);
+                DidMerge(true, false)
+            }
+            (None, None) => DidMerge(false, false),
+        }
+    }
+}
+
+/// Whether a 32-bit value fits the 12-bit signed immediate an I-type instruction
+/// can encode.
+fn fits_imm12(n: i32) -> bool {
+    (-2048..=2047).contains(&n)
+}
+
+/// The handful of binary ops whose result can be folded when both sources are known
+/// constants.
+#[derive(Clone, Copy)]
+enum BinOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Sll,
+    Srl,
+}
+
+impl BinOp {
+    fn eval(self, a: i32, b: i32) -> i32 {
+        match self {
+            BinOp::Add => a.wrapping_add(b),
+            BinOp::Sub => a.wrapping_sub(b),
+            BinOp::And => a & b,
+            BinOp::Or => a | b,
+            BinOp::Xor => a ^ b,
+            BinOp::Sll => a.wrapping_shl((b & 0x1f) as u32),
+            BinOp::Srl => ((a as u32).wrapping_shr((b & 0x1f) as u32)) as i32,
+        }
+    }
+}
+
+/// Folds `(op dest c1 c2)` into `(addi dest x0 result)` once the `ConstantFold`
+/// analysis confirms both sources are known constants and the result fits the
+/// 12-bit signed immediate an `addi` can encode; declines otherwise.
+///
+/// A result that doesn't fit would need a `lui`+`addi` pair to materialize -- two
+/// instructions, not one -- and an egg `Applier` can only union eclasses standing
+/// for the same sort of value. This rule matches a single instruction eclass
+/// (inside a `seq`, not the `seq` itself), and egg patterns can't splice a
+/// variable number of `seq` children either (see `fold_consecutive_addi`'s doc
+/// comment for the same limitation), so there's no sound way to graft a
+/// two-instruction replacement in here. Declining just leaves the original
+/// register-register op in place; the only pass that materializes an out-of-range
+/// constant via `lui`+`addi` is `fold_consecutive_addi`, and only for a chain of
+/// same-dest `addi`s starting from `x0`.
+struct FoldBinOp {
+    op: BinOp,
+    c1: Var,
+    c2: Var,
+    rhs: Pattern<RiscvLang>,
+}
+
+impl Applier<RiscvLang, ConstantFold> for FoldBinOp {
+    fn apply_one(
+        &self,
+        egraph: &mut EGraph<RiscvLang, ConstantFold>,
+        eclass: Id,
+        subst: &Subst,
+        searcher_ast: Option<&PatternAst<RiscvLang>>,
+        rule_name: Symbol,
+    ) -> Vec<Id> {
+        let (Some(c1), Some(c2)) = (egraph[subst[self.c1]].data, egraph[subst[self.c2]].data) else {
+            return vec![];
+        };
+
+        let result = self.op.eval(c1, c2);
+        if !fits_imm12(result) {
+            return vec![];
+        }
+
+        let folded = egraph.add(RiscvLang::Num(result));
+        let mut subst = subst.clone();
+        subst.insert("?folded".parse().unwrap(), folded);
+        self.rhs.apply_one(egraph, eclass, &subst, searcher_ast, rule_name)
+    }
+}
+
+/// A register the abstract-interpretation pass below has proven holds a known
+/// constant at some program point.
+fn sym_of(node: &RiscvLang) -> Option<Symbol> {
+    match node {
+        RiscvLang::Var(s) => Some(*s),
+        _ => None,
+    }
+}
+
+fn num_of(node: &RiscvLang) -> Option<i32> {
+    match node {
+        RiscvLang::Num(n) => Some(*n),
+        _ => None,
+    }
+}
+
+/// Rewrites a register-register op into its immediate form once one of its sources
+/// is a known constant (per `known`), e.g. `add d, s, k` => `addi d, s, k`. `sub`
+/// only folds on its second (subtrahend) operand, and `sll`/`srl` only on the shift
+/// amount, since those are the only positions RISC-V actually has an immediate
+/// encoding for.
+fn try_strength_reduce(
+    node: &RiscvLang,
+    nodes: &mut Vec<RiscvLang>,
+    known: &HashMap<Symbol, i32>,
+) -> Option<RiscvLang> {
+    use RiscvLang::*;
+
+    let reg_imm = |id: Id, nodes: &Vec<RiscvLang>| -> Option<i32> {
+        sym_of(&nodes[usize::from(id)])
+            .and_then(|s| known.get(&s).copied())
+            .filter(|k| fits_imm12(*k))
+    };
+    let shift_imm = |id: Id, nodes: &Vec<RiscvLang>| -> Option<i32> {
+        sym_of(&nodes[usize::from(id)])
+            .and_then(|s| known.get(&s).copied())
+            .filter(|k| (0..32).contains(k))
+    };
+
+    match node {
+        Add([d, s1, s2]) => {
+            if let Some(k) = reg_imm(*s2, nodes) {
+                let imm = push_node(nodes, Num(k));
+                return Some(Addi([*d, *s1, imm]));
+            }
+            reg_imm(*s1, nodes).map(|k| {
+                let imm = push_node(nodes, Num(k));
+                Addi([*d, *s2, imm])
+            })
+        }
+        And([d, s1, s2]) => {
+            if let Some(k) = reg_imm(*s2, nodes) {
+                let imm = push_node(nodes, Num(k));
+                return Some(Andi([*d, *s1, imm]));
+            }
+            reg_imm(*s1, nodes).map(|k| {
+                let imm = push_node(nodes, Num(k));
+                Andi([*d, *s2, imm])
+            })
+        }
+        Or([d, s1, s2]) => {
+            if let Some(k) = reg_imm(*s2, nodes) {
+                let imm = push_node(nodes, Num(k));
+                return Some(Ori([*d, *s1, imm]));
+            }
+            reg_imm(*s1, nodes).map(|k| {
+                let imm = push_node(nodes, Num(k));
+                Ori([*d, *s2, imm])
+            })
+        }
+        Xor([d, s1, s2]) => {
+            if let Some(k) = reg_imm(*s2, nodes) {
+                let imm = push_node(nodes, Num(k));
+                return Some(Xori([*d, *s1, imm]));
+            }
+            reg_imm(*s1, nodes).map(|k| {
+                let imm = push_node(nodes, Num(k));
+                Xori([*d, *s2, imm])
+            })
+        }
+        Sub([d, s1, s2]) => reg_imm(*s2, nodes).map(|k| {
+            let imm = push_node(nodes, Num(-k));
+            Addi([*d, *s1, imm])
+        }),
+        Sll([d, s1, s2]) => shift_imm(*s2, nodes).map(|k| {
+            let imm = push_node(nodes, Num(k));
+            Slli([*d, *s1, imm])
+        }),
+        Srl([d, s1, s2]) => shift_imm(*s2, nodes).map(|k| {
+            let imm = push_node(nodes, Num(k));
+            Srli([*d, *s1, imm])
+        }),
+        _ => None,
+    }
+}
+
+/// Forward constant propagation over each basic block: tracks which registers hold
+/// a known value, seeded by `addi d, x0, k` and by `lui`+`addi` pairs that
+/// materialize a 32-bit constant, and uses that to strength-reduce later
+/// register-register ops into their immediate form via [`try_strength_reduce`].
+/// Resets at every block boundary, the same conservative choice the liveness and
+/// register-allocation passes above make.
+///
+/// `try_strength_reduce` appends the fresh `Num` immediates a rewrite needs to the
+/// end of `nodes`, so a rewritten instruction can't be written back into its
+/// original slot: its children would then have a *larger* id than the instruction
+/// itself, violating `RecExpr`'s child-id-ordering invariant (harmless to
+/// `emit_asm`, which indexes directly, but it corrupts `to_string()`/`Display`,
+/// which walks children-before-parent). Instead each rewritten instruction is
+/// pushed as a fresh node at the end, and the top-level `Seq` is rebuilt from
+/// `seq_children` with that position updated to the new id.
+fn propagate_constants(expr: &RecExpr<RiscvLang>) -> RecExpr<RiscvLang> {
+    let root = Id::from(expr.as_ref().len() - 1);
+    let mut nodes: Vec<RiscvLang> = expr.as_ref().to_vec();
+
+    let mut seq_children = match &nodes[usize::from(root)] {
+        RiscvLang::Seq(ids) => ids.clone(),
+        _ => return expr.clone(),
+    };
+    let position: HashMap<Id, usize> =
+        seq_children.iter().enumerate().map(|(i, &id)| (id, i)).collect();
+    let blocks = split_basic_blocks(&seq_children, expr);
+    let x0 = Symbol::from("x0");
+
+    for block in &blocks {
+        let mut known: HashMap<Symbol, i32> = HashMap::new();
+
+        for (pos, &id) in block.iter().enumerate() {
+            let idx = usize::from(id);
+            let node = nodes[idx].clone();
+
+            match &node {
+                RiscvLang::Addi([d, s, i]) => {
+                    let dest = sym_of(&nodes[usize::from(*d)]);
+                    let src = sym_of(&nodes[usize::from(*s)]);
+                    let imm = num_of(&nodes[usize::from(*i)]);
+                    match (dest, src, imm) {
+                        (Some(d), Some(s), Some(n)) if s == x0 => {
+                            known.insert(d, n);
+                        }
+                        (Some(d), Some(s), Some(n)) => match known.get(&s) {
+                            Some(&base) => {
+                                known.insert(d, base.wrapping_add(n));
+                            }
+                            None => {
+                                known.remove(&d);
+                            }
+                        },
+                        (Some(d), _, _) => {
+                            known.remove(&d);
+                        }
+                        _ => {}
+                    }
+                }
+                RiscvLang::Lui([d, i]) => {
+                    if let (Some(d), Some(upper)) =
+                        (sym_of(&nodes[usize::from(*d)]), num_of(&nodes[usize::from(*i)]))
+                    {
+                        known.remove(&d);
+                        // A bare `lui` only becomes a known constant once the
+                        // following `addi` fills in the low 12 bits.
+                        if let Some(&next_id) = block.get(pos + 1) {
+                            if let RiscvLang::Addi([d2, s2, i2]) = &nodes[usize::from(next_id)] {
+                                let d2 = sym_of(&nodes[usize::from(*d2)]);
+                                let s2 = sym_of(&nodes[usize::from(*s2)]);
+                                let lower = num_of(&nodes[usize::from(*i2)]);
+                                if d2 == Some(d) && s2 == Some(d) && lower.is_some() {
+                                    // Record what the `lui` leaves in `d`; the `addi`
+                                    // arm adds the low 12 bits on the next instruction.
+                                    known.insert(d, upper << 12);
+                                }
+                            }
+                        }
+                    }
+                }
+                _ => {
+                    if let Some(rewritten) = try_strength_reduce(&node, &mut nodes, &known) {
+                        let new_id = push_node(&mut nodes, rewritten);
+                        seq_children[position[&id]] = new_id;
+                    }
+                    if let Some(d) = instr_io(&node, expr).def {
+                        known.remove(&d);
+                    }
+                }
+            }
+        }
+    }
+
+    nodes.push(RiscvLang::Seq(seq_children));
+    RecExpr::from(nodes)
+}
+
+/// Merges adjacent `addi`s to the same destination into one, computing the sum
+/// directly instead of relying on an egg rewrite rule: an egg pattern variable is
+/// just an ordinary single-`Id` binding no matter how its name is spelled (there's
+/// no splice/ellipsis syntax for a `Vec<Id>`-backed `seq` node), so a `rw!` attempt
+/// at this can only ever match a `seq` with an exact, fixed number of children.
+/// Walks each block left to right instead, collapsing a whole chain of same-dest
+/// `addi`s in one pass. When the running sum doesn't fit a 12-bit signed immediate,
+/// a chain starting from `x0` is materialized via `lui`+`addi` instead of a single
+/// `addi`; a chain starting from any other register is left unmerged.
+///
+/// A merged/materialized instruction's children can be freshly-pushed `Num`
+/// immediates with a *larger* id than the instruction's own original slot, so
+/// (as in `propagate_constants` above) it can't be written back into that slot in
+/// place without violating `RecExpr`'s child-id-ordering invariant. `staged` holds
+/// each original id's pending replacement content so the chain-walk below can keep
+/// reading a position's latest value without writing through to `nodes` yet; each
+/// staged node is pushed fresh (after its children) once the block is done, and
+/// `seq_children` is updated to point at the new id.
+fn fold_consecutive_addi(expr: &RecExpr<RiscvLang>) -> RecExpr<RiscvLang> {
+    let root = Id::from(expr.as_ref().len() - 1);
+    let mut nodes: Vec<RiscvLang> = expr.as_ref().to_vec();
+
+    let mut seq_children = match &nodes[usize::from(root)] {
+        RiscvLang::Seq(ids) => ids.clone(),
+        _ => return expr.clone(),
+    };
+    let position: HashMap<Id, usize> =
+        seq_children.iter().enumerate().map(|(i, &id)| (id, i)).collect();
+    let mut blocks = split_basic_blocks(&seq_children, expr);
+    let mut staged: HashMap<Id, RiscvLang> = HashMap::new();
+    let at = |id: Id, nodes: &[RiscvLang], staged: &HashMap<Id, RiscvLang>| {
+        staged.get(&id).cloned().unwrap_or_else(|| nodes[usize::from(id)].clone())
+    };
+
+    for block in &mut blocks {
+        let mut i = 0;
+        while i + 1 < block.len() {
+            let first_id = block[i];
+            let second_id = block[i + 1];
+            let first = at(first_id, &nodes, &staged);
+            let second = at(second_id, &nodes, &staged);
+
+            let (RiscvLang::Addi([d1, s1, imm1]), RiscvLang::Addi([d2, s2, imm2])) = (&first, &second)
+            else {
+                i += 1;
+                continue;
+            };
+            let dest = sym_of(&nodes[usize::from(*d1)]);
+            let same_dest_chain = dest.is_some()
+                && dest == sym_of(&nodes[usize::from(*d2)])
+                && dest == sym_of(&nodes[usize::from(*s2)]);
+            let consts = num_of(&nodes[usize::from(*imm1)]).zip(num_of(&nodes[usize::from(*imm2)]));
+
+            let (Some((c1, c2)), true) = (consts, same_dest_chain) else {
+                i += 1;
+                continue;
+            };
+
+            let (d, s) = (*d1, *s1);
+            let sum = c1.wrapping_add(c2);
+            if fits_imm12(sum) {
+                let imm = push_node(&mut nodes, RiscvLang::Num(sum));
+                staged.insert(first_id, RiscvLang::Addi([d, s, imm]));
+                staged.remove(&second_id);
+                nodes[usize::from(second_id)] = RiscvLang::Nop;
+                // Drop the now-nop'd slot so a third addi in the chain is compared
+                // directly against the merged one, collapsing the whole chain.
+                block.remove(i + 1);
+            } else if sym_of(&nodes[usize::from(s)]) != Some(Symbol::from("x0")) {
+                // `lui`+`addi` can only load a constant; it can't add one to `s`.
+                i += 1;
+            } else {
+                // Same split a `li` of this size would need: the high 20 bits via
+                // `lui`, sign-extended against the low 12 bits the `addi` fills in.
+                let upper = (sum >> 12).wrapping_add(i32::from(sum & 0x800 != 0));
+                let lower = sum.wrapping_sub(upper << 12);
+                let upper_id = push_node(&mut nodes, RiscvLang::Num(upper));
+                let lower_id = push_node(&mut nodes, RiscvLang::Num(lower));
+                staged.insert(first_id, RiscvLang::Lui([d, upper_id]));
+                staged.insert(second_id, RiscvLang::Addi([d, d, lower_id]));
+                i += 1;
+            }
+        }
+    }
+
+    for (id, node) in staged {
+        let new_id = push_node(&mut nodes, node);
+        seq_children[position[&id]] = new_id;
+    }
+    nodes.push(RiscvLang::Seq(seq_children));
+    RecExpr::from(nodes)
+}
+
+/// Runs the chosen extractor over `egraph`, returning the winning cost alongside the
+/// extracted expression.
+fn extract_best(
+    egraph: &EGraph<RiscvLang, ConstantFold>,
+    root: Id,
+    kind: ExtractKind,
+) -> (usize, RecExpr<RiscvLang>) {
+    match kind {
+        ExtractKind::AstSize => Extractor::new(egraph, AstSize).find_best(root),
+        ExtractKind::Latency => Extractor::new(egraph, Latency).find_best(root),
     }
 }
 
@@ -130,11 +1489,11 @@ fn main() -> io::Result<()> {
     let output_file = "output.s";
 
     let input_content = fs::read_to_string(input_file)?;
-    let expr: RecExpr<RiscvLang> = input_content.parse().unwrap();
+    let expr: RecExpr<RiscvLang> = parse_asm(&input_content);
 
     println!("Initial program:\n{}", expr);
 
-    let rules: &[Rewrite<RiscvLang, ()>] = &[
+    let rules: &[Rewrite<RiscvLang, ConstantFold>] = &[
         // Add x0: (add dest src x0) => (addi dest src 0)
         rw!("add-zero"; "(add ?dest ?src x0)" => "(addi ?dest ?src 0)"),
         // Add x0 commute: (add dest x0 src) => (addi dest src 0)
@@ -231,50 +1590,192 @@ fn main() -> io::Result<()> {
         rw!("xor-zero"; "(xor ?dest ?src x0)" => "(addi ?dest ?src 0)"),
         rw!("xor-zero-commute"; "(xor ?dest x0 ?src)" => "(addi ?dest ?src 0)"),
 
-        // Merge consecutive addi instructions to the same dest
-        //    e.g. (seq (addi x1 x2 5) (addi x1 x1 3) ...) => (seq (addi x1 x2 8) ...)
-        rw!("fold-consecutive-addi";
-            "(seq (addi ?dest ?src (Num ?c1)) (addi ?dest ?dest (Num ?c2)) ?rest*)"
-            => "(seq (addi ?dest ?src (Num (+ ?c1 ?c2))) ?rest*)"
-        ),
-
-        // Fold sub-one: (sub ?dest ?src (Num 1)) => (addi ?dest ?src -1)
+        // Fold sub-one: (sub ?dest ?src 1) => (addi ?dest ?src -1)
         rw!("fold-sub-one";
-            "(sub ?dest ?src (Num 1))"
+            "(sub ?dest ?src 1)"
             => "(addi ?dest ?src -1)"
         ),
 
-        // Cancel out consecutive addi + addi with negative immediate:
-        //    (seq (addi ?d ?s c) (addi ?d ?d -c)) => (seq)
-        rw!("fold-addi-then-subi";
-            "(seq (addi ?dest ?src (Num ?c1)) (addi ?dest ?dest (Num ?c2)) ?rest*)"
-            => "(seq ?rest*)"
-        ),
-
         // Fold lw/sw of the same register+address in direct sequence => no-op
         //    (seq (lw ?r ?addr) (sw ?r ?addr) ...) => just remove them
         rw!("fold-lw-sw-same-address";
             "(seq (lw ?r ?addr) (sw ?r ?addr) ?rest*)"
             => "(seq ?rest*)"
         ),
+
+        // Merging consecutive addi instructions to the same dest needs matching a
+        // variable number of seq children, which egg patterns can't express here (see
+        // `fold_consecutive_addi`'s doc comment); that merge runs as its own pass in
+        // `main` instead.
+
+        // Fold an R-type op whose two sources are both known constants into a single
+        // `addi dest, x0, result`; this needs real arithmetic, which only a custom
+        // Applier can do (unlike the consecutive-addi merge above, a fixed 3-child
+        // pattern like this one is fine as an egg rule).
+        rw!("fold-add-const"; "(add ?dest ?c1 ?c2)" => { FoldBinOp {
+            op: BinOp::Add, c1: "?c1".parse().unwrap(), c2: "?c2".parse().unwrap(),
+            rhs: "(addi ?dest x0 ?folded)".parse().unwrap(),
+        } }),
+        rw!("fold-sub-const"; "(sub ?dest ?c1 ?c2)" => { FoldBinOp {
+            op: BinOp::Sub, c1: "?c1".parse().unwrap(), c2: "?c2".parse().unwrap(),
+            rhs: "(addi ?dest x0 ?folded)".parse().unwrap(),
+        } }),
+        rw!("fold-and-const"; "(and ?dest ?c1 ?c2)" => { FoldBinOp {
+            op: BinOp::And, c1: "?c1".parse().unwrap(), c2: "?c2".parse().unwrap(),
+            rhs: "(addi ?dest x0 ?folded)".parse().unwrap(),
+        } }),
+        rw!("fold-or-const"; "(or ?dest ?c1 ?c2)" => { FoldBinOp {
+            op: BinOp::Or, c1: "?c1".parse().unwrap(), c2: "?c2".parse().unwrap(),
+            rhs: "(addi ?dest x0 ?folded)".parse().unwrap(),
+        } }),
+        rw!("fold-xor-const"; "(xor ?dest ?c1 ?c2)" => { FoldBinOp {
+            op: BinOp::Xor, c1: "?c1".parse().unwrap(), c2: "?c2".parse().unwrap(),
+            rhs: "(addi ?dest x0 ?folded)".parse().unwrap(),
+        } }),
+        rw!("fold-sll-const"; "(sll ?dest ?c1 ?c2)" => { FoldBinOp {
+            op: BinOp::Sll, c1: "?c1".parse().unwrap(), c2: "?c2".parse().unwrap(),
+            rhs: "(addi ?dest x0 ?folded)".parse().unwrap(),
+        } }),
+        rw!("fold-srl-const"; "(srl ?dest ?c1 ?c2)" => { FoldBinOp {
+            op: BinOp::Srl, c1: "?c1".parse().unwrap(), c2: "?c2".parse().unwrap(),
+            rhs: "(addi ?dest x0 ?folded)".parse().unwrap(),
+        } }),
     ];
 
-    let runner = Runner::default()
+    let runner = Runner::<RiscvLang, ConstantFold>::default()
         .with_expr(&expr)
         .with_iter_limit(100)
         .run(rules);
 
-    let extractor = Extractor::new(&runner.egraph, AstSize);
-    let (best_cost, best_expr) = extractor.find_best(runner.roots[0]);
+    let (best_cost, best_expr) = extract_best(&runner.egraph, runner.roots[0], extract_kind_from_args());
+
+    // Merges adjacent same-dest addi chains; not expressible as an egg rewrite rule
+    // (see fold_consecutive_addi's doc comment), so it runs as its own pass here.
+    let folded_addi_expr = fold_consecutive_addi(&best_expr);
+
+    // Catch dead computations the rewrite rules above don't express directly, e.g. a
+    // value that's computed and then never read before the function returns.
+    let live_expr = liveness_dce(&folded_addi_expr, &["x10", "x11"]);
 
-    let optimized_expr = remove_nops(&best_expr.to_string());
+    // Turns any symbolic `Var` registers the input used as virtuals into legal
+    // physical registers; a no-op when the program already only names x0..x31.
+    let allocated_expr = allocate_registers(&live_expr);
 
-    println!("\nOptimized program (cost {}):\n{}", best_cost, optimized_expr);
+    // Re-derive constants lost to register allocation's renaming (e.g. an `addi
+    // d, x0, k` whose `d` got renamed still marks `d` as known) and strength-reduce
+    // any register-register op that now has a constant operand.
+    let folded_expr = propagate_constants(&allocated_expr);
 
+    println!(
+        "\nOptimized program (cost {}):\n{}",
+        best_cost,
+        remove_nops(&folded_expr.to_string())
+    );
+
+    let assembly = emit_asm(&folded_expr);
     let mut output = fs::File::create(output_file)?;
-    write!(output, "{}", optimized_expr)?;
+    write!(output, "{}", assembly)?;
 
     println!("\nOptimized program written to {}", output_file);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_emit_roundtrip_recovers_pseudo_instructions() {
+        let asm = "li a0, 5\naddi a0, a0, 1\nret";
+        let expr = parse_asm(asm);
+        assert_eq!(emit_asm(&expr), asm);
+    }
+
+    #[test]
+    fn liveness_dce_removes_dead_computation() {
+        let asm = "addi a2, x0, 99\naddi a0, x0, 1\nret";
+        let expr = parse_asm(asm);
+        let live = liveness_dce(&expr, &["x10"]);
+        assert!(!emit_asm(&live).contains("a2"));
+    }
+
+    #[test]
+    fn allocate_registers_assigns_physical_registers() {
+        let asm = "addi v0, x0, 1\naddi v1, x0, 2\nadd a0, v0, v1\nret";
+        let expr = parse_asm(asm);
+        let allocated = allocate_registers(&expr);
+        assert!(virtual_registers(&allocated).is_empty());
+    }
+
+    #[test]
+    fn interference_graph_does_not_force_independent_virtuals_to_interfere() {
+        let mut asm = String::new();
+        for i in 0..5 {
+            asm.push_str(&format!("addi v{i}, x0, {i}\nsw v{i}, 0(sp)\n"));
+        }
+        let expr = parse_asm(&asm);
+        let virtuals = virtual_registers(&expr);
+        let (graph, _) = build_interference_graph(&expr, &virtuals);
+        for v in &virtuals {
+            assert!(graph[v].is_empty(), "{v:?} should not interfere with any other virtual");
+        }
+    }
+
+    #[test]
+    fn virtual_registers_excludes_synthetic_spill_addresses() {
+        let expr = parse_asm("lw v0, 4(sp)\nsw v0, 8(sp)");
+        let addr = Symbol::from("4(x2)");
+        assert!(!virtual_registers(&expr).contains(&addr));
+        assert!(is_spill_address(addr));
+    }
+
+    #[test]
+    fn propagate_constants_strength_reduces_add_with_known_operand() {
+        let expr = parse_asm("addi t2, x0, 5\nadd a0, a1, t2\nret");
+        let folded = propagate_constants(&expr);
+        assert!(emit_asm(&folded).contains("addi a0, a1, 5"));
+    }
+
+    #[test]
+    fn fold_consecutive_addi_merges_a_three_instruction_chain() {
+        let expr = parse_asm("addi t0, x0, 1\naddi t0, t0, 2\naddi t0, t0, 3\nret");
+        let folded = fold_consecutive_addi(&expr);
+        assert_eq!(emit_asm(&folded), "li t0, 6\nret");
+    }
+
+    #[test]
+    fn latency_cost_function_weighs_expensive_ops_higher() {
+        let add_expr = parse_asm("add a0, a1, a2");
+        let mul_expr = parse_asm("mul a0, a1, a2");
+        let mut latency = Latency;
+        assert!(latency.cost_rec(&mul_expr) > latency.cost_rec(&add_expr));
+    }
+
+    fn fold_add_const_rule() -> Rewrite<RiscvLang, ConstantFold> {
+        rw!("fold-add-const"; "(add ?dest ?c1 ?c2)" => { FoldBinOp {
+            op: BinOp::Add, c1: "?c1".parse().unwrap(), c2: "?c2".parse().unwrap(),
+            rhs: "(addi ?dest x0 ?folded)".parse().unwrap(),
+        } })
+    }
+
+    #[test]
+    fn fold_bin_op_folds_constants_that_fit_imm12() {
+        let expr = parse_asm("add a0, 5, 3");
+        let runner = Runner::<RiscvLang, ConstantFold>::default()
+            .with_expr(&expr)
+            .run(&[fold_add_const_rule()]);
+        let (_, best) = extract_best(&runner.egraph, runner.roots[0], ExtractKind::AstSize);
+        assert!(emit_asm(&best).contains("li a0, 8"));
+    }
+
+    #[test]
+    fn fold_bin_op_declines_when_result_does_not_fit_imm12() {
+        let expr = parse_asm("add a0, 2000, 2000");
+        let runner = Runner::<RiscvLang, ConstantFold>::default()
+            .with_expr(&expr)
+            .run(&[fold_add_const_rule()]);
+        let (_, best) = extract_best(&runner.egraph, runner.roots[0], ExtractKind::AstSize);
+        assert!(emit_asm(&best).contains("add a0, 2000, 2000"));
+    }
+}